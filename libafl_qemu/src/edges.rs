@@ -0,0 +1,275 @@
+//! Edge coverage instrumentation, feeding the shared sancov counters map so
+//! QEMU-driven and natively-instrumented targets report coverage the same
+//! way.
+use core::fmt::Debug;
+
+use libafl::{executors::ExitKind, inputs::UsesInput, observers::ObserversTuple};
+use libafl_qemu_sys::GuestAddr;
+use libafl_targets::COUNTERS_MAPS;
+
+#[cfg(emulation_mode = "systemmode")]
+use crate::helper::{HasCompositeInstrumentationFilter, QemuInstrumentationPagingFilter};
+use crate::{
+    helper::{
+        hash_me, HasInstrumentationFilter, IsFilter, QemuHelper, QemuHelperTuple,
+        QemuInstrumentationAddressRangeFilter,
+    },
+    hooks::QemuHooks,
+    Qemu,
+};
+
+/// How [`QemuEdgeCoverageHelper`] folds control-flow history into the map
+/// index, trading map sparsity for path sensitivity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QemuEdgeCoverageMode {
+    /// Hash only the current `src -> dst` edge (the historical behavior).
+    Edge,
+    /// Fold the last `n` block hashes into the index via a shift register.
+    Ngram { n: u32 },
+    /// XOR in the call-site hash on `call`, and restore it on `ret`.
+    CallContext,
+}
+
+/// Collects edge coverage by hashing `src -> dst` transitions into the
+/// shared [`COUNTERS_MAPS`] used by the `sancov` instrumentation.
+#[derive(Debug)]
+pub struct QemuEdgeCoverageHelper {
+    address_filter: QemuInstrumentationAddressRangeFilter,
+    #[cfg(emulation_mode = "systemmode")]
+    paging_filter: QemuInstrumentationPagingFilter,
+    mode: QemuEdgeCoverageMode,
+    /// Shift register (n-gram mode) or call-site XOR accumulator
+    /// (call-context mode) of recent control-flow history.
+    ctx_register: u64,
+}
+
+impl QemuEdgeCoverageHelper {
+    #[must_use]
+    pub fn new(address_filter: QemuInstrumentationAddressRangeFilter) -> Self {
+        Self::with_mode(address_filter, QemuEdgeCoverageMode::Edge)
+    }
+
+    #[must_use]
+    pub fn with_mode(
+        address_filter: QemuInstrumentationAddressRangeFilter,
+        mode: QemuEdgeCoverageMode,
+    ) -> Self {
+        Self {
+            address_filter,
+            #[cfg(emulation_mode = "systemmode")]
+            paging_filter: QemuInstrumentationPagingFilter::None,
+            mode,
+            ctx_register: 0,
+        }
+    }
+
+    fn map_size() -> usize {
+        unsafe { COUNTERS_MAPS.iter().map(|map| map.len()).sum() }
+    }
+
+    fn hit(index: usize) {
+        unsafe {
+            let mut remaining = index;
+            for map in &mut *core::ptr::addr_of_mut!(COUNTERS_MAPS) {
+                if remaining < map.len() {
+                    map[remaining] = map[remaining].wrapping_add(1);
+                    return;
+                }
+                remaining -= map.len();
+            }
+        }
+    }
+
+    /// Number of bits of each block hash folded into the shift register per
+    /// transition in [`QemuEdgeCoverageMode::Ngram`] mode.
+    const NGRAM_CHUNK_BITS: u32 = 8;
+
+    fn trace(&mut self, src: GuestAddr, dst: GuestAddr) {
+        let block_hash = hash_me(src as u64) ^ hash_me((dst as u64).rotate_left(1));
+
+        let folded_hash = match self.mode {
+            QemuEdgeCoverageMode::Edge => block_hash,
+            QemuEdgeCoverageMode::Ngram { .. } | QemuEdgeCoverageMode::CallContext => {
+                hash_me(block_hash ^ self.ctx_register)
+            }
+        };
+
+        // Retain the last `n - 1` blocks' low byte in a shift register, so
+        // the map index depends on a real slice of path history rather than
+        // a single bit of it.
+        if let QemuEdgeCoverageMode::Ngram { n } = self.mode {
+            let retained_bits = (Self::NGRAM_CHUNK_BITS * n.saturating_sub(1)).min(63);
+            let mask = (1_u64 << retained_bits) - 1;
+            self.ctx_register = ((self.ctx_register << Self::NGRAM_CHUNK_BITS)
+                | (block_hash & 0xFF))
+                & mask;
+        }
+
+        let map_size = Self::map_size();
+        if map_size == 0 {
+            return;
+        }
+
+        Self::hit((folded_hash as usize) % map_size);
+    }
+
+    /// Called on `call` instructions in [`QemuEdgeCoverageMode::CallContext`]
+    /// mode: folds the call-site hash into the context register.
+    pub fn enter_call(&mut self, call_site: GuestAddr) {
+        if self.mode == QemuEdgeCoverageMode::CallContext {
+            self.ctx_register ^= hash_me(call_site as u64);
+        }
+    }
+
+    /// Called on `ret` instructions in [`QemuEdgeCoverageMode::CallContext`]
+    /// mode: restores the context register to its pre-call value.
+    pub fn leave_call(&mut self, call_site: GuestAddr) {
+        if self.mode == QemuEdgeCoverageMode::CallContext {
+            self.ctx_register ^= hash_me(call_site as u64);
+        }
+    }
+}
+
+impl HasInstrumentationFilter<QemuInstrumentationAddressRangeFilter> for QemuEdgeCoverageHelper {
+    fn filter(&self) -> &QemuInstrumentationAddressRangeFilter {
+        &self.address_filter
+    }
+
+    fn filter_mut(&mut self) -> &mut QemuInstrumentationAddressRangeFilter {
+        &mut self.address_filter
+    }
+}
+
+#[cfg(emulation_mode = "systemmode")]
+impl HasInstrumentationFilter<QemuInstrumentationPagingFilter> for QemuEdgeCoverageHelper {
+    fn filter(&self) -> &QemuInstrumentationPagingFilter {
+        &self.paging_filter
+    }
+
+    fn filter_mut(&mut self) -> &mut QemuInstrumentationPagingFilter {
+        &mut self.paging_filter
+    }
+}
+
+fn on_edge_exec<QT, S>(hooks: &mut QemuHooks<QT, S>, src: GuestAddr, dst: GuestAddr)
+where
+    S: UsesInput,
+    QT: QemuHelperTuple<S>,
+{
+    #[cfg(emulation_mode = "systemmode")]
+    let paging_id = hooks.qemu().current_paging_id();
+
+    let helper = hooks
+        .helpers_mut()
+        .match_first_type_mut::<QemuEdgeCoverageHelper>()
+        .expect("QemuEdgeCoverageHelper not found in the helper tuple");
+
+    #[cfg(emulation_mode = "systemmode")]
+    let in_scope = helper.composite_filter().allowed((src, paging_id));
+    #[cfg(emulation_mode = "usermode")]
+    let in_scope = helper.address_filter.allowed(src);
+
+    if !in_scope {
+        return;
+    }
+
+    helper.trace(src, dst);
+}
+
+fn on_call_exec<QT, S>(hooks: &mut QemuHooks<QT, S>, call_site: GuestAddr, _target: GuestAddr)
+where
+    S: UsesInput,
+    QT: QemuHelperTuple<S>,
+{
+    let helper = hooks
+        .helpers_mut()
+        .match_first_type_mut::<QemuEdgeCoverageHelper>()
+        .expect("QemuEdgeCoverageHelper not found in the helper tuple");
+
+    helper.enter_call(call_site);
+}
+
+fn on_ret_exec<QT, S>(hooks: &mut QemuHooks<QT, S>, call_site: GuestAddr, _target: GuestAddr)
+where
+    S: UsesInput,
+    QT: QemuHelperTuple<S>,
+{
+    let helper = hooks
+        .helpers_mut()
+        .match_first_type_mut::<QemuEdgeCoverageHelper>()
+        .expect("QemuEdgeCoverageHelper not found in the helper tuple");
+
+    helper.leave_call(call_site);
+}
+
+impl<S> QemuHelper<S> for QemuEdgeCoverageHelper
+where
+    S: UsesInput,
+{
+    fn init_hooks<QT>(&self, hooks: &QemuHooks<QT, S>)
+    where
+        QT: QemuHelperTuple<S>,
+    {
+        hooks.edges(
+            |_hooks: &mut QemuHooks<QT, S>, _state, src, dst| Some((src, dst)),
+            on_edge_exec::<QT, S>,
+        );
+
+        if self.mode == QemuEdgeCoverageMode::CallContext {
+            hooks.calls(on_call_exec::<QT, S>);
+            hooks.rets(on_ret_exec::<QT, S>);
+        }
+    }
+
+    fn pre_exec(&mut self, _emulator: &Qemu, _input: &S::Input) {
+        self.ctx_register = 0;
+    }
+
+    fn post_exec<OT>(
+        &mut self,
+        _emulator: &Qemu,
+        _input: &S::Input,
+        _observers: &mut OT,
+        _exit_kind: &mut ExitKind,
+    ) where
+        OT: ObserversTuple<S>,
+    {
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn call_context_round_trips_on_matching_ret() {
+        let mut helper = QemuEdgeCoverageHelper::with_mode(
+            QemuInstrumentationAddressRangeFilter::None,
+            QemuEdgeCoverageMode::CallContext,
+        );
+        let before = helper.ctx_register;
+
+        helper.enter_call(0x1000);
+        assert_ne!(helper.ctx_register, before);
+
+        helper.leave_call(0x1000);
+        assert_eq!(helper.ctx_register, before);
+    }
+
+    #[test]
+    fn ngram_register_retains_more_than_one_bit_of_history() {
+        let mut helper = QemuEdgeCoverageHelper::with_mode(
+            QemuInstrumentationAddressRangeFilter::None,
+            QemuEdgeCoverageMode::Ngram { n: 3 },
+        );
+
+        helper.trace(0x1000, 0x1004);
+        let after_first = helper.ctx_register;
+        helper.trace(0x2000, 0x2004);
+
+        // The first transition's contribution must still be visible in the
+        // upper byte after the second transition shifts in its own byte.
+        assert_ne!(helper.ctx_register, after_first);
+        assert_eq!((helper.ctx_register >> 8) & 0xFF, after_first & 0xFF);
+    }
+}
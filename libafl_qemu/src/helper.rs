@@ -146,12 +146,19 @@ where
 pub enum QemuFilterList<T: IsFilter + Debug + Clone> {
     AllowList(T),
     DenyList(T),
+    /// Instrument only when every sub-filter allows the parameter.
+    All(Vec<QemuFilterList<T>>),
+    /// Instrument when at least one sub-filter allows the parameter.
+    Any(Vec<QemuFilterList<T>>),
+    /// Invert a sub-filter's decision.
+    Not(Box<QemuFilterList<T>>),
     None,
 }
 
 impl<T> IsFilter for QemuFilterList<T>
 where
     T: IsFilter + Clone,
+    T::FilterParameter: Clone,
 {
     type FilterParameter = T::FilterParameter;
 
@@ -159,11 +166,38 @@ where
         match self {
             QemuFilterList::AllowList(allow_list) => allow_list.allowed(filter_parameter),
             QemuFilterList::DenyList(deny_list) => !deny_list.allowed(filter_parameter),
+            QemuFilterList::All(filters) => filters
+                .iter()
+                .all(|filter| filter.allowed(filter_parameter.clone())),
+            QemuFilterList::Any(filters) => filters
+                .iter()
+                .any(|filter| filter.allowed(filter_parameter.clone())),
+            QemuFilterList::Not(filter) => !filter.allowed(filter_parameter),
             QemuFilterList::None => true,
         }
     }
 }
 
+/// ANDs two filters together, e.g. an address-range filter with a paging
+/// filter, so a transition is only instrumented when both agree.
+#[derive(Debug, Clone)]
+pub struct TupleFilter<A, B> {
+    pub first: A,
+    pub second: B,
+}
+
+impl<A, B> IsFilter for TupleFilter<A, B>
+where
+    A: IsFilter,
+    B: IsFilter,
+{
+    type FilterParameter = (A::FilterParameter, B::FilterParameter);
+
+    fn allowed(&self, filter_parameter: Self::FilterParameter) -> bool {
+        self.first.allowed(filter_parameter.0) && self.second.allowed(filter_parameter.1)
+    }
+}
+
 pub type QemuInstrumentationPagingFilter = QemuFilterList<HashSet<GuestPhysAddr>>;
 
 impl<H> IsFilter for HashSet<GuestPhysAddr, H>
@@ -206,6 +240,36 @@ where
     }
 }
 
+/// An address-range filter ANDed with a paging filter, so a helper can be
+/// restricted to a module range within a single process even in systemmode.
+#[cfg(emulation_mode = "systemmode")]
+pub type QemuInstrumentationAddressToPagingFilter =
+    TupleFilter<QemuInstrumentationAddressRangeFilter, QemuInstrumentationPagingFilter>;
+
+/// Blanket accessor for the composite address+paging filter, for any helper
+/// that already tracks both filters individually.
+#[cfg(emulation_mode = "systemmode")]
+pub trait HasCompositeInstrumentationFilter:
+    HasInstrumentationFilter<QemuInstrumentationAddressRangeFilter>
+    + HasInstrumentationFilter<QemuInstrumentationPagingFilter>
+{
+    fn composite_filter(&self) -> QemuInstrumentationAddressToPagingFilter {
+        TupleFilter {
+            first: HasInstrumentationFilter::<QemuInstrumentationAddressRangeFilter>::filter(self)
+                .clone(),
+            second: HasInstrumentationFilter::<QemuInstrumentationPagingFilter>::filter(self)
+                .clone(),
+        }
+    }
+}
+
+#[cfg(emulation_mode = "systemmode")]
+impl<H> HasCompositeInstrumentationFilter for H where
+    H: HasInstrumentationFilter<QemuInstrumentationAddressRangeFilter>
+        + HasInstrumentationFilter<QemuInstrumentationPagingFilter>
+{
+}
+
 #[cfg(emulation_mode = "usermode")]
 pub trait StdInstrumentationFilter:
     HasInstrumentationFilter<QemuInstrumentationAddressRangeFilter>
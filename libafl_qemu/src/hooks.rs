@@ -0,0 +1,321 @@
+//! Central hook-registration point shared by every [`QemuHelper`](crate::helper::QemuHelper):
+//! builds the edge/memory/syscall/instruction hooks every helper in this
+//! crate is written against on top of whichever [`QemuBackend`] is
+//! installed -- the patched QEMU shipped by `libafl_qemu_sys` by default, or
+//! [`TcgPluginBackend`](crate::plugin::TcgPluginBackend) for an unmodified
+//! QEMU -- so a helper never has to know which one is actually running
+//! underneath it.
+use core::{cell::Cell, fmt::Debug};
+
+use libafl::inputs::UsesInput;
+use libafl_qemu_sys::GuestAddr;
+
+use crate::{helper::QemuHelperTuple, plugin::QemuBackend, Qemu};
+
+/// Drives the patched QEMU shipped by `libafl_qemu_sys`, the default and
+/// historically the only backend `libafl_qemu` supported. Unlike
+/// [`TcgPluginBackend`](crate::plugin::TcgPluginBackend), the patched QEMU
+/// can tag call/ret instructions and interrupt a running vCPU directly, so
+/// this backend implements every [`QemuBackend`] hook instead of falling
+/// back to the trait's no-op defaults.
+#[derive(Debug)]
+pub struct PatchedQemuBackend {
+    qemu: Qemu,
+}
+
+impl PatchedQemuBackend {
+    #[must_use]
+    pub fn new(qemu: Qemu) -> Self {
+        Self { qemu }
+    }
+}
+
+impl QemuBackend for PatchedQemuBackend {
+    fn on_translate_block(&mut self, callback: Box<dyn FnMut(GuestAddr) -> bool>) {
+        self.qemu.add_block_hook(callback);
+    }
+
+    fn on_instruction_exec(&mut self, callback: Box<dyn FnMut(GuestAddr)>) {
+        self.qemu.add_instruction_hook(callback);
+    }
+
+    fn on_memory_access(&mut self, callback: Box<dyn FnMut(GuestAddr, GuestAddr, u8, bool)>) {
+        self.qemu.add_memory_hook(callback);
+    }
+
+    fn on_syscall(
+        &mut self,
+        pre: Box<dyn FnMut(GuestAddr, i32, &mut [GuestAddr; 6]) -> bool>,
+        post: Box<dyn FnMut(i32, GuestAddr) -> GuestAddr>,
+    ) {
+        self.qemu.add_syscall_hooks(pre, post);
+    }
+
+    fn on_call(&mut self, callback: Box<dyn FnMut(GuestAddr, GuestAddr)>) {
+        self.qemu.add_call_hook(callback);
+    }
+
+    fn on_ret(&mut self, callback: Box<dyn FnMut(GuestAddr, GuestAddr)>) {
+        self.qemu.add_ret_hook(callback);
+    }
+
+    fn flush_jit(&mut self) {
+        self.qemu.flush_jit();
+    }
+
+    fn atexit(&mut self) {}
+}
+
+type EdgeExec<QT, S> = fn(&mut QemuHooks<QT, S>, GuestAddr, GuestAddr);
+type MemExec<QT, S> = fn(&mut QemuHooks<QT, S>, GuestAddr, GuestAddr, u8, u64);
+type InstructionExec<QT, S> = fn(&mut QemuHooks<QT, S>, GuestAddr);
+type CallExec<QT, S> = fn(&mut QemuHooks<QT, S>, GuestAddr, GuestAddr);
+type SyscallPreExec<QT, S> = fn(&mut QemuHooks<QT, S>, GuestAddr, i32, &mut [GuestAddr; 6]) -> bool;
+type SyscallPostExec<QT, S> = fn(&mut QemuHooks<QT, S>, i32, GuestAddr) -> GuestAddr;
+
+/// Owns the helper tuple and the installed [`QemuBackend`], and is the sole
+/// point every [`QemuHelper`](crate::helper::QemuHelper) registers its hooks
+/// through. `edges`/`reads`/`writes`/`instruction_closure` are built on top
+/// of the backend's three exec primitives (translate/instruction/memory),
+/// `calls`/`rets` on its call/ret primitives, and `syscalls`/`syscalls_after`
+/// on its syscall primitive, so the same helper code drives either backend
+/// without change.
+///
+/// The `gen` closure each registration method accepts mirrors QEMU's own
+/// translate-time/exec-time split (decide once per block, act on every
+/// exec); every helper in this crate currently always instruments, so `gen`
+/// is accepted for API parity with that model but not consulted -- the
+/// per-exec helpers already apply their own filters.
+pub struct QemuHooks<QT, S>
+where
+    S: UsesInput,
+    QT: QemuHelperTuple<S>,
+{
+    qemu: Qemu,
+    helpers: QT,
+    backend: Box<dyn QemuBackend>,
+    last_pc: Cell<Option<GuestAddr>>,
+    edge: Cell<Option<EdgeExec<QT, S>>>,
+    read: Cell<Option<MemExec<QT, S>>>,
+    write: Cell<Option<MemExec<QT, S>>>,
+    instruction: Cell<Option<InstructionExec<QT, S>>>,
+    call: Cell<Option<CallExec<QT, S>>>,
+    ret: Cell<Option<CallExec<QT, S>>>,
+    syscall_pre: Cell<Option<SyscallPreExec<QT, S>>>,
+    syscall_post: Cell<Option<SyscallPostExec<QT, S>>>,
+}
+
+impl<QT, S> QemuHooks<QT, S>
+where
+    S: UsesInput,
+    QT: QemuHelperTuple<S>,
+{
+    /// Builds the hooks for `helpers` and wires them to `backend`, pinning
+    /// the result on the heap: every exec primitive captures a raw pointer
+    /// back to this box so it can be called from the backend's own
+    /// callbacks, so `hooks` must not be moved out of the returned box.
+    #[must_use]
+    pub fn new(qemu: Qemu, helpers: QT, backend: Box<dyn QemuBackend>) -> Box<Self> {
+        let mut hooks = Box::new(Self {
+            qemu,
+            helpers,
+            backend,
+            last_pc: Cell::new(None),
+            edge: Cell::new(None),
+            read: Cell::new(None),
+            write: Cell::new(None),
+            instruction: Cell::new(None),
+            call: Cell::new(None),
+            ret: Cell::new(None),
+            syscall_pre: Cell::new(None),
+            syscall_post: Cell::new(None),
+        });
+        hooks.wire_backend();
+        hooks
+    }
+
+    /// Replaces the installed backend and re-wires every already-registered
+    /// hook against it, so switching from the patched backend to
+    /// [`TcgPluginBackend`](crate::plugin::TcgPluginBackend) (or back)
+    /// actually changes what drives instrumentation.
+    pub fn set_backend(&mut self, backend: Box<dyn QemuBackend>) {
+        self.backend = backend;
+        self.wire_backend();
+    }
+
+    fn wire_backend(&mut self) {
+        let self_ptr: *mut Self = self;
+
+        self.backend.on_translate_block(Box::new(move |pc| {
+            // SAFETY: `self_ptr` was derived from `self` in `new`/
+            // `set_backend`, which require the caller to keep this
+            // `QemuHooks` pinned for as long as the backend may call back
+            // into it.
+            let hooks = unsafe { &mut *self_ptr };
+            hooks.dispatch_translate_block(pc)
+        }));
+        self.backend.on_instruction_exec(Box::new(move |pc| {
+            let hooks = unsafe { &mut *self_ptr };
+            hooks.dispatch_instruction_exec(pc);
+        }));
+        self.backend
+            .on_memory_access(Box::new(move |pc, addr, size, is_write| {
+                let hooks = unsafe { &mut *self_ptr };
+                hooks.dispatch_memory_access(pc, addr, size, is_write);
+            }));
+        self.backend.on_syscall(
+            Box::new(move |pc, nr, args| {
+                let hooks = unsafe { &mut *self_ptr };
+                hooks.dispatch_syscall_pre(pc, nr, args)
+            }),
+            Box::new(move |nr, result| {
+                let hooks = unsafe { &mut *self_ptr };
+                hooks.dispatch_syscall_post(nr, result)
+            }),
+        );
+        self.backend.on_call(Box::new(move |call_site, target| {
+            let hooks = unsafe { &mut *self_ptr };
+            hooks.dispatch_call(call_site, target);
+        }));
+        self.backend.on_ret(Box::new(move |call_site, target| {
+            let hooks = unsafe { &mut *self_ptr };
+            hooks.dispatch_ret(call_site, target);
+        }));
+    }
+
+    fn dispatch_translate_block(&mut self, _pc: GuestAddr) -> bool {
+        self.edge.get().is_some()
+            || self.read.get().is_some()
+            || self.write.get().is_some()
+            || self.instruction.get().is_some()
+            || self.call.get().is_some()
+            || self.ret.get().is_some()
+    }
+
+    fn dispatch_instruction_exec(&mut self, pc: GuestAddr) {
+        if let Some(edge_exec) = self.edge.get() {
+            if let Some(src) = self.last_pc.get() {
+                edge_exec(self, src, pc);
+            }
+        }
+        self.last_pc.set(Some(pc));
+
+        if let Some(instruction_exec) = self.instruction.get() {
+            instruction_exec(self, pc);
+        }
+    }
+
+    fn dispatch_memory_access(&mut self, pc: GuestAddr, addr: GuestAddr, size: u8, is_write: bool) {
+        let value = self.qemu.read_guest_value(addr, size);
+        if is_write {
+            if let Some(write_exec) = self.write.get() {
+                write_exec(self, pc, addr, size, value);
+            }
+        } else if let Some(read_exec) = self.read.get() {
+            read_exec(self, pc, addr, size, value);
+        }
+    }
+
+    fn dispatch_syscall_pre(
+        &mut self,
+        pc: GuestAddr,
+        syscall_nr: i32,
+        args: &mut [GuestAddr; 6],
+    ) -> bool {
+        match self.syscall_pre.get() {
+            Some(syscall_pre) => syscall_pre(self, pc, syscall_nr, args),
+            None => true,
+        }
+    }
+
+    fn dispatch_syscall_post(&mut self, syscall_nr: i32, result: GuestAddr) -> GuestAddr {
+        match self.syscall_post.get() {
+            Some(syscall_post) => syscall_post(self, syscall_nr, result),
+            None => result,
+        }
+    }
+
+    fn dispatch_call(&mut self, call_site: GuestAddr, target: GuestAddr) {
+        if let Some(call_exec) = self.call.get() {
+            call_exec(self, call_site, target);
+        }
+    }
+
+    fn dispatch_ret(&mut self, call_site: GuestAddr, target: GuestAddr) {
+        if let Some(ret_exec) = self.ret.get() {
+            ret_exec(self, call_site, target);
+        }
+    }
+
+    #[must_use]
+    pub fn qemu(&self) -> &Qemu {
+        &self.qemu
+    }
+
+    pub fn helpers_mut(&mut self) -> &mut QT {
+        &mut self.helpers
+    }
+
+    pub fn edges(
+        &self,
+        _gen: impl FnMut(&mut Self, Option<&mut S>, GuestAddr, GuestAddr) -> Option<(GuestAddr, GuestAddr)>
+            + 'static,
+        exec: EdgeExec<QT, S>,
+    ) {
+        self.edge.set(Some(exec));
+    }
+
+    pub fn reads(
+        &self,
+        _gen: impl FnMut(&mut Self, Option<&mut S>, GuestAddr, GuestAddr) -> Option<(GuestAddr, GuestAddr)>
+            + 'static,
+        exec: MemExec<QT, S>,
+    ) {
+        self.read.set(Some(exec));
+    }
+
+    pub fn writes(
+        &self,
+        _gen: impl FnMut(&mut Self, Option<&mut S>, GuestAddr, GuestAddr) -> Option<(GuestAddr, GuestAddr)>
+            + 'static,
+        exec: MemExec<QT, S>,
+    ) {
+        self.write.set(Some(exec));
+    }
+
+    pub fn instruction_closure(
+        &self,
+        _gen: impl FnMut(&mut Self, Option<&mut S>, GuestAddr) -> bool + 'static,
+        exec: InstructionExec<QT, S>,
+    ) {
+        self.instruction.set(Some(exec));
+    }
+
+    pub fn calls(&self, exec: CallExec<QT, S>) {
+        self.call.set(Some(exec));
+    }
+
+    pub fn rets(&self, exec: CallExec<QT, S>) {
+        self.ret.set(Some(exec));
+    }
+
+    pub fn syscalls(&self, exec: SyscallPreExec<QT, S>) {
+        self.syscall_pre.set(Some(exec));
+    }
+
+    pub fn syscalls_after(&self, exec: SyscallPostExec<QT, S>) {
+        self.syscall_post.set(Some(exec));
+    }
+}
+
+impl<QT, S> Debug for QemuHooks<QT, S>
+where
+    S: UsesInput,
+    QT: QemuHelperTuple<S> + Debug,
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("QemuHooks")
+            .field("helpers", &self.helpers)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,176 @@
+//! A deterministic, instruction-counted timeout, for reproducible crash
+//! triage and corpus minimization across machines with different wall-clock
+//! characteristics.
+use core::fmt::Debug;
+
+use libafl::{
+    executors::ExitKind,
+    inputs::UsesInput,
+    observers::{Observer, ObserversTuple},
+    Error,
+};
+use libafl_bolts::Named;
+use libafl_qemu_sys::GuestAddr;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    helper::{HasInstrumentationFilter, IsFilter, QemuHelper, QemuHelperTuple,
+        QemuInstrumentationAddressRangeFilter},
+    hooks::QemuHooks,
+    Qemu,
+};
+
+/// Counts executed instructions (filtered to the address range of interest)
+/// and reports [`ExitKind::Timeout`] once a configurable budget is exceeded,
+/// instead of relying on a wall-clock timeout.
+#[derive(Debug)]
+pub struct QemuInstructionBudgetHelper {
+    filter: QemuInstrumentationAddressRangeFilter,
+    budget: u64,
+    count: u64,
+    exceeded: bool,
+}
+
+impl QemuInstructionBudgetHelper {
+    #[must_use]
+    pub fn new(budget: u64, filter: QemuInstrumentationAddressRangeFilter) -> Self {
+        Self {
+            filter,
+            budget,
+            count: 0,
+            exceeded: false,
+        }
+    }
+
+    /// Number of in-scope instructions executed during the last run.
+    #[must_use]
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Records one in-scope instruction, returning `true` the instant the
+    /// budget is first crossed so the caller can interrupt the vCPU right
+    /// away rather than only flagging the overrun for `post_exec` to read
+    /// once the harness eventually returns.
+    fn tick(&mut self, pc: GuestAddr) -> bool {
+        if !self.filter.allowed(pc) {
+            return false;
+        }
+
+        self.count += 1;
+        if self.count > self.budget && !self.exceeded {
+            self.exceeded = true;
+            return true;
+        }
+        false
+    }
+}
+
+impl HasInstrumentationFilter<QemuInstrumentationAddressRangeFilter> for QemuInstructionBudgetHelper {
+    fn filter(&self) -> &QemuInstrumentationAddressRangeFilter {
+        &self.filter
+    }
+
+    fn filter_mut(&mut self) -> &mut QemuInstrumentationAddressRangeFilter {
+        &mut self.filter
+    }
+}
+
+fn on_instruction_exec<QT, S>(hooks: &mut QemuHooks<QT, S>, pc: GuestAddr)
+where
+    S: UsesInput,
+    QT: QemuHelperTuple<S>,
+{
+    let helper = hooks
+        .helpers_mut()
+        .match_first_type_mut::<QemuInstructionBudgetHelper>()
+        .expect("QemuInstructionBudgetHelper not found in the helper tuple");
+
+    let just_exceeded = helper.tick(pc);
+
+    if just_exceeded {
+        // Stop the vCPU right here instead of waiting for the harness to
+        // return control naturally: a runaway/hung target is exactly the
+        // case this budget exists to catch, and it will never reach
+        // `post_exec` on its own.
+        hooks.qemu().interrupt_vcpu();
+    }
+}
+
+impl<S> QemuHelper<S> for QemuInstructionBudgetHelper
+where
+    S: UsesInput,
+{
+    fn init_hooks<QT>(&self, hooks: &QemuHooks<QT, S>)
+    where
+        QT: QemuHelperTuple<S>,
+    {
+        hooks.instruction_closure(
+            |_hooks: &mut QemuHooks<QT, S>, _state, _pc| true,
+            on_instruction_exec::<QT, S>,
+        );
+    }
+
+    fn pre_exec(&mut self, _emulator: &Qemu, _input: &S::Input) {
+        self.count = 0;
+        self.exceeded = false;
+    }
+
+    fn post_exec<OT>(
+        &mut self,
+        _emulator: &Qemu,
+        _input: &S::Input,
+        observers: &mut OT,
+        exit_kind: &mut ExitKind,
+    ) where
+        OT: ObserversTuple<S>,
+    {
+        if let Some(observer) = observers.match_first_type_mut::<QemuInstructionBudgetObserver>() {
+            observer.count = self.count;
+        }
+
+        if self.exceeded {
+            *exit_kind = ExitKind::Timeout;
+        }
+    }
+}
+
+/// Exposes the final in-scope instruction count from the last run to the
+/// corpus scheduler, analogously to [`QemuMemoryAccessObserver`](crate::memory::QemuMemoryAccessObserver)
+/// and [`QemuSyscallObserver`](crate::syscall::QemuSyscallObserver).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QemuInstructionBudgetObserver {
+    name: String,
+    count: u64,
+}
+
+impl QemuInstructionBudgetObserver {
+    #[must_use]
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            count: 0,
+        }
+    }
+
+    #[must_use]
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+impl Named for QemuInstructionBudgetObserver {
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl<S> Observer<S> for QemuInstructionBudgetObserver
+where
+    S: UsesInput,
+{
+    fn pre_exec(&mut self, _state: &mut S, _input: &S::Input) -> Result<(), Error> {
+        self.count = 0;
+        Ok(())
+    }
+}
@@ -0,0 +1,221 @@
+//! Memory-access instrumentation, feeding a `CmpLog`-style value-profile map
+//! that lets the fuzzer solve magic-byte and checksum style comparisons.
+use core::fmt::Debug;
+
+use libafl::{
+    executors::ExitKind,
+    inputs::UsesInput,
+    observers::{Observer, ObserversTuple},
+    Error,
+};
+use libafl_bolts::Named;
+use libafl_qemu_sys::GuestAddr;
+use serde::{Deserialize, Serialize};
+
+#[cfg(emulation_mode = "systemmode")]
+use crate::helper::{HasCompositeInstrumentationFilter, QemuInstrumentationPagingFilter};
+use crate::{
+    helper::{
+        hash_me, HasInstrumentationFilter, IsFilter, QemuHelper, QemuHelperTuple,
+        QemuInstrumentationAddressRangeFilter,
+    },
+    hooks::QemuHooks,
+    Qemu,
+};
+
+/// Number of slots in the memory-access value-profile map.
+pub const MEM_ACCESS_MAP_SIZE: usize = 1 << 16;
+
+/// A single value-profile slot: the address, size and direction of the last
+/// in-scope access at a given site, the value it loaded/stored, and how
+/// many times that site has fired.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct MemAccessEntry {
+    pub addr: GuestAddr,
+    pub size: u8,
+    pub is_write: bool,
+    pub value: u64,
+    pub hits: u32,
+}
+
+/// Map of access-site hash (via [`hash_me`]) to the last observed value and
+/// hit count, read by [`QemuMemoryAccessHelper`]'s observer in `post_exec`.
+pub static mut MEM_ACCESS_MAP: [MemAccessEntry; MEM_ACCESS_MAP_SIZE] = [MemAccessEntry {
+    addr: 0,
+    size: 0,
+    is_write: false,
+    value: 0,
+    hits: 0,
+}; MEM_ACCESS_MAP_SIZE];
+
+/// Records the address, size and value of in-scope loads/stores into
+/// [`MEM_ACCESS_MAP`], following QEMU's TCG plugin memory-access callback
+/// model (address, size, direction, value).
+#[derive(Debug)]
+pub struct QemuMemoryAccessHelper {
+    address_filter: QemuInstrumentationAddressRangeFilter,
+    #[cfg(emulation_mode = "systemmode")]
+    paging_filter: QemuInstrumentationPagingFilter,
+}
+
+impl QemuMemoryAccessHelper {
+    #[must_use]
+    pub fn new(address_filter: QemuInstrumentationAddressRangeFilter) -> Self {
+        Self {
+            address_filter,
+            #[cfg(emulation_mode = "systemmode")]
+            paging_filter: QemuInstrumentationPagingFilter::None,
+        }
+    }
+
+    fn record(&self, pc: GuestAddr, addr: GuestAddr, size: u8, is_write: bool, value: u64) {
+        let slot = (hash_me(pc as u64) as usize) % MEM_ACCESS_MAP_SIZE;
+        unsafe {
+            let entry = &mut MEM_ACCESS_MAP[slot];
+            entry.addr = addr;
+            entry.size = size;
+            entry.is_write = is_write;
+            entry.value = value;
+            entry.hits = entry.hits.saturating_add(1);
+        }
+    }
+}
+
+impl HasInstrumentationFilter<QemuInstrumentationAddressRangeFilter> for QemuMemoryAccessHelper {
+    fn filter(&self) -> &QemuInstrumentationAddressRangeFilter {
+        &self.address_filter
+    }
+
+    fn filter_mut(&mut self) -> &mut QemuInstrumentationAddressRangeFilter {
+        &mut self.address_filter
+    }
+}
+
+#[cfg(emulation_mode = "systemmode")]
+impl HasInstrumentationFilter<QemuInstrumentationPagingFilter> for QemuMemoryAccessHelper {
+    fn filter(&self) -> &QemuInstrumentationPagingFilter {
+        &self.paging_filter
+    }
+
+    fn filter_mut(&mut self) -> &mut QemuInstrumentationPagingFilter {
+        &mut self.paging_filter
+    }
+}
+
+fn trace_memory_access<QT, S>(
+    hooks: &mut QemuHooks<QT, S>,
+    pc: GuestAddr,
+    addr: GuestAddr,
+    size: u8,
+    is_write: bool,
+    value: u64,
+) where
+    S: UsesInput,
+    QT: QemuHelperTuple<S>,
+{
+    #[cfg(emulation_mode = "systemmode")]
+    let paging_id = hooks.qemu().current_paging_id();
+
+    let helper = hooks
+        .helpers_mut()
+        .match_first_type_mut::<QemuMemoryAccessHelper>()
+        .expect("QemuMemoryAccessHelper not found in the helper tuple");
+
+    #[cfg(emulation_mode = "systemmode")]
+    let in_scope = helper.composite_filter().allowed((addr, paging_id));
+    #[cfg(emulation_mode = "usermode")]
+    let in_scope = helper.address_filter.allowed(addr);
+
+    if in_scope {
+        helper.record(pc, addr, size, is_write, value);
+    }
+}
+
+impl<S> QemuHelper<S> for QemuMemoryAccessHelper
+where
+    S: UsesInput,
+{
+    fn init_hooks<QT>(&self, hooks: &QemuHooks<QT, S>)
+    where
+        QT: QemuHelperTuple<S>,
+    {
+        hooks.reads(
+            |_hooks: &mut QemuHooks<QT, S>, _state, pc, addr| Some((pc, addr)),
+            |hooks: &mut QemuHooks<QT, S>, pc, addr, size, value| {
+                trace_memory_access(hooks, pc, addr, size, false, value);
+            },
+        );
+        hooks.writes(
+            |_hooks: &mut QemuHooks<QT, S>, _state, pc, addr| Some((pc, addr)),
+            |hooks: &mut QemuHooks<QT, S>, pc, addr, size, value| {
+                trace_memory_access(hooks, pc, addr, size, true, value);
+            },
+        );
+    }
+
+    fn post_exec<OT>(
+        &mut self,
+        _emulator: &Qemu,
+        _input: &S::Input,
+        _observers: &mut OT,
+        _exit_kind: &mut ExitKind,
+    ) where
+        OT: ObserversTuple<S>,
+    {
+    }
+}
+
+/// Snapshots [`MEM_ACCESS_MAP`] for the corpus scheduler to read after each
+/// run, similarly to how `MapObserver`s expose sancov coverage maps.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QemuMemoryAccessObserver {
+    name: String,
+    map: Vec<MemAccessEntry>,
+}
+
+impl QemuMemoryAccessObserver {
+    #[must_use]
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            map: vec![MemAccessEntry::default(); MEM_ACCESS_MAP_SIZE],
+        }
+    }
+
+    #[must_use]
+    pub fn map(&self) -> &[MemAccessEntry] {
+        &self.map
+    }
+}
+
+impl Named for QemuMemoryAccessObserver {
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl<S> Observer<S> for QemuMemoryAccessObserver
+where
+    S: UsesInput,
+{
+    fn pre_exec(&mut self, _state: &mut S, _input: &S::Input) -> Result<(), Error> {
+        unsafe {
+            for slot in &mut MEM_ACCESS_MAP {
+                *slot = MemAccessEntry::default();
+            }
+        }
+        Ok(())
+    }
+
+    fn post_exec(
+        &mut self,
+        _state: &mut S,
+        _input: &S::Input,
+        _exit_kind: &ExitKind,
+    ) -> Result<(), Error> {
+        unsafe {
+            self.map.copy_from_slice(&MEM_ACCESS_MAP);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,466 @@
+//! An alternative backend that drives an unmodified, distro-packaged QEMU
+//! through its stable `qemu-plugin.h` TCG plugin interface, instead of the
+//! patched QEMU shipped by `libafl_qemu_sys`.
+//!
+//! [`QemuHelper`](crate::helper::QemuHelper)/[`QemuHelperTuple`](crate::helper::QemuHelperTuple)
+//! implementations (edge coverage, the instrumentation filters, ...) are
+//! written against [`QemuBackend`] rather than against the patched hooks
+//! directly, so the same helper works unchanged on either backend.
+use core::ffi::{c_uint, c_void};
+use std::sync::Mutex;
+
+use libafl_qemu_sys::GuestAddr;
+
+use crate::hooks::QemuHooks;
+
+/// Minimal subset of `qemu-plugin.h` needed to drive an unmodified QEMU:
+/// the translation-block translate callback, per-instruction and
+/// per-memory-access exec callbacks, the syscall and syscall-return
+/// callbacks, a reset (flush) call and an atexit hook.
+mod ffi {
+    use core::ffi::{c_int, c_uint, c_void};
+
+    pub type QemuPluginId = u64;
+
+    /// Opaque handle to a translated block, valid only inside the
+    /// translate callback.
+    #[repr(C)]
+    pub struct QemuPluginTb {
+        _private: [u8; 0],
+    }
+
+    /// Opaque handle to a single instruction inside a [`QemuPluginTb`].
+    #[repr(C)]
+    pub struct QemuPluginInsn {
+        _private: [u8; 0],
+    }
+
+    pub const QEMU_PLUGIN_CB_NO_REGS: c_int = 0;
+    pub const QEMU_PLUGIN_MEM_RW: c_int = 2;
+
+    extern "C" {
+        pub fn qemu_plugin_register_vcpu_tb_trans_cb(
+            id: QemuPluginId,
+            callback: extern "C" fn(id: QemuPluginId, tb: *mut QemuPluginTb),
+        );
+
+        pub fn qemu_plugin_tb_n_insns(tb: *const QemuPluginTb) -> usize;
+
+        pub fn qemu_plugin_tb_get_insn(tb: *const QemuPluginTb, idx: usize)
+            -> *mut QemuPluginInsn;
+
+        pub fn qemu_plugin_insn_vaddr(insn: *const QemuPluginInsn) -> u64;
+
+        pub fn qemu_plugin_register_vcpu_insn_exec_cb(
+            insn: *mut QemuPluginInsn,
+            callback: extern "C" fn(vcpu_index: c_uint, userdata: *mut c_void),
+            flags: c_int,
+            userdata: *mut c_void,
+        );
+
+        pub fn qemu_plugin_register_vcpu_mem_cb(
+            insn: *mut QemuPluginInsn,
+            callback: extern "C" fn(
+                vcpu_index: c_uint,
+                info: u64,
+                vaddr: u64,
+                userdata: *mut c_void,
+            ),
+            flags: c_int,
+            rw: c_int,
+            userdata: *mut c_void,
+        );
+
+        pub fn qemu_plugin_register_atexit_cb(
+            id: QemuPluginId,
+            callback: extern "C" fn(id: QemuPluginId, userdata: *mut c_void),
+            userdata: *mut c_void,
+        );
+
+        pub fn qemu_plugin_reset(id: QemuPluginId, callback: Option<extern "C" fn()>);
+
+        pub fn qemu_plugin_register_vcpu_syscall_cb(
+            id: QemuPluginId,
+            callback: extern "C" fn(
+                id: QemuPluginId,
+                vcpu_index: c_uint,
+                num: i64,
+                a1: u64,
+                a2: u64,
+                a3: u64,
+                a4: u64,
+                a5: u64,
+                a6: u64,
+            ),
+        );
+
+        pub fn qemu_plugin_register_vcpu_syscall_ret_cb(
+            id: QemuPluginId,
+            callback: extern "C" fn(id: QemuPluginId, vcpu_index: c_uint, num: i64, ret: i64),
+        );
+    }
+}
+
+/// Hook installation, abstracted over the patched-QEMU backend
+/// (`libafl_qemu_sys`) and the unmodified-QEMU [`TcgPluginBackend`].
+///
+/// A backend only has to expose the handful of callback points the TCG
+/// plugin API offers; [`QemuHooks`] builds the richer edge/memory/syscall
+/// hooks on top of these.
+pub trait QemuBackend {
+    /// Called at translation time, before the code is cached, with a guest
+    /// `pc`; returning `false` leaves it uninstrumented. [`TcgPluginBackend`]
+    /// calls it once for each instruction of a translated block.
+    /// [`QemuHooks`] returns `true` whenever any exec hook is registered and
+    /// does not consult the address or paging filters here; each helper
+    /// applies its own filters at exec time.
+    fn on_translate_block(&mut self, callback: Box<dyn FnMut(GuestAddr) -> bool>);
+
+    /// Called on every executed instruction of an instrumented block.
+    fn on_instruction_exec(&mut self, callback: Box<dyn FnMut(GuestAddr)>);
+
+    /// Called on every in-scope memory access: `(pc, addr, size, is_write)`.
+    fn on_memory_access(&mut self, callback: Box<dyn FnMut(GuestAddr, GuestAddr, u8, bool)>);
+
+    /// Called before and after every syscall: the pre callback may rewrite
+    /// `args` in place and returning `false` short-circuits the syscall;
+    /// the post callback may rewrite the return value the guest observes.
+    ///
+    /// Backends that cannot rewrite syscalls (the TCG plugin API only
+    /// observes them) still call `pre`/`post` for their recording
+    /// side-effects, but ignore the returned values.
+    fn on_syscall(
+        &mut self,
+        pre: Box<dyn FnMut(GuestAddr, i32, &mut [GuestAddr; 6]) -> bool>,
+        post: Box<dyn FnMut(i32, GuestAddr) -> GuestAddr>,
+    );
+
+    /// Called on `call` instructions, with the call site and target. Most
+    /// TCG plugin builds cannot single out call instructions without
+    /// target-specific disassembly, so the default implementation is a
+    /// no-op; backends that can identify them (the patched QEMU backend)
+    /// override this.
+    fn on_call(&mut self, _callback: Box<dyn FnMut(GuestAddr, GuestAddr)>) {}
+
+    /// Called on `ret` instructions, with the matching call site and
+    /// target. See [`Self::on_call`] for why this defaults to a no-op.
+    fn on_ret(&mut self, _callback: Box<dyn FnMut(GuestAddr, GuestAddr)>) {}
+
+    /// Invalidates the backend's translation cache. [`QemuHooks`] does not
+    /// call it, and [`HasInstrumentationFilter::update_filter`](crate::helper::HasInstrumentationFilter::update_filter)
+    /// flushes through `Qemu::flush_jit` rather than through the installed
+    /// backend.
+    fn flush_jit(&mut self);
+
+    /// Called once the guest exits, to release any plugin-side state.
+    fn atexit(&mut self);
+}
+
+/// Per-plugin-instance callback storage. `qemu_plugin_install` hands us a
+/// `qemu_plugin_id_t`; the exec, memory and atexit trampolines reach this
+/// struct through `userdata` pointers, and the translate and syscall
+/// trampolines look it up by that id in [`REGISTERED_STATES`]. It is
+/// allocated on the heap by [`TcgPluginBackend::install`] and freed when the
+/// [`TcgPluginBackend`] is dropped.
+struct PluginState {
+    id: ffi::QemuPluginId,
+    on_translate_block: Option<Box<dyn FnMut(GuestAddr) -> bool>>,
+    on_instruction_exec: Option<Box<dyn FnMut(GuestAddr)>>,
+    on_memory_access: Option<Box<dyn FnMut(GuestAddr, GuestAddr, u8, bool)>>,
+    on_syscall_pre: Option<Box<dyn FnMut(GuestAddr, i32, &mut [GuestAddr; 6]) -> bool>>,
+    on_syscall_post: Option<Box<dyn FnMut(i32, GuestAddr) -> GuestAddr>>,
+    on_atexit: Option<Box<dyn FnMut()>>,
+    /// `pc` of the last-executed instrumented instruction on this vCPU,
+    /// used to give [`QemuBackend::on_syscall`] a caller `pc`: unlike the
+    /// per-instruction exec/mem callbacks, `qemu-plugin.h`'s syscall
+    /// callbacks carry no `pc` of their own.
+    last_pc: GuestAddr,
+}
+
+/// Drives an unmodified QEMU through `qemu-plugin.h`: translation-block
+/// translate callback, instruction/memory-access exec callbacks, flush and
+/// atexit. This lets users fuzz with distro-packaged QEMU builds instead of
+/// maintaining a forked emulator.
+pub struct TcgPluginBackend {
+    state: *mut PluginState,
+}
+
+impl TcgPluginBackend {
+    /// # Safety
+    /// `id` must be the `qemu_plugin_id_t` that `qemu_plugin_install` handed
+    /// to this plugin's install callback.
+    #[must_use]
+    pub unsafe fn install(id: ffi::QemuPluginId) -> Self {
+        let state = Box::into_raw(Box::new(PluginState {
+            id,
+            on_translate_block: None,
+            on_instruction_exec: None,
+            on_memory_access: None,
+            on_syscall_pre: None,
+            on_syscall_post: None,
+            on_atexit: None,
+            last_pc: 0,
+        }));
+
+        ffi::qemu_plugin_register_atexit_cb(id, trampoline_atexit, state.cast::<c_void>());
+        register_state(state);
+
+        Self { state }
+    }
+
+    fn state(&mut self) -> &mut PluginState {
+        // SAFETY: `state` was built by `Box::into_raw` in `install` and is
+        // only ever accessed through this backend or the trampolines below,
+        // which run on the vCPU thread that owns it.
+        unsafe { &mut *self.state }
+    }
+}
+
+impl Drop for TcgPluginBackend {
+    fn drop(&mut self) {
+        let state = self.state;
+        unregister_state(state);
+
+        // SAFETY: matches the `Box::into_raw` in `install`; QEMU has
+        // already called our atexit trampoline by the time the backend
+        // driving it is torn down.
+        unsafe {
+            drop(Box::from_raw(state));
+        }
+    }
+}
+
+/// Userdata for a single instruction's exec/mem callbacks: the instruction's
+/// own `pc`, resolved once in the translate callback (the `qemu_plugin_insn`
+/// handle it came from is only valid during translation), plus the owning
+/// plugin's state. Leaked for the lifetime of its translated block, which is
+/// how `qemu-plugin.h` expects per-instruction userdata to be kept alive.
+struct InsnUserdata {
+    state: *mut PluginState,
+    pc: GuestAddr,
+}
+
+/// Looks up the [`PluginState`] registered for `id` in the process-wide
+/// [`REGISTERED_STATES`]. `qemu-plugin.h` invokes every one of these
+/// trampolines from whichever vCPU thread triggered them -- translation and
+/// syscalls alike can happen on any vCPU, not just the one that ran
+/// `install` -- so this must not be thread-local.
+fn find_state(id: ffi::QemuPluginId) -> Option<*mut PluginState> {
+    REGISTERED_STATES
+        .lock()
+        .expect("REGISTERED_STATES mutex poisoned")
+        .iter()
+        .map(|&candidate| candidate as *mut PluginState)
+        .find(|candidate| unsafe { (**candidate).id } == id)
+}
+
+extern "C" fn trampoline_tb_trans(id: ffi::QemuPluginId, tb: *mut ffi::QemuPluginTb) {
+    let Some(state_ptr) = find_state(id) else {
+        return;
+    };
+    let state = unsafe { &mut *state_ptr };
+
+    let n_insns = unsafe { ffi::qemu_plugin_tb_n_insns(tb) };
+    for idx in 0..n_insns {
+        let insn = unsafe { ffi::qemu_plugin_tb_get_insn(tb, idx) };
+        let pc = unsafe { ffi::qemu_plugin_insn_vaddr(insn) } as GuestAddr;
+
+        let allowed = state
+            .on_translate_block
+            .as_mut()
+            .is_none_or(|callback| callback(pc));
+        if !allowed {
+            continue;
+        }
+
+        if state.on_instruction_exec.is_some() || state.on_memory_access.is_some() {
+            let userdata = Box::into_raw(Box::new(InsnUserdata {
+                state: state_ptr,
+                pc,
+            }))
+            .cast::<c_void>();
+
+            if state.on_instruction_exec.is_some() {
+                unsafe {
+                    ffi::qemu_plugin_register_vcpu_insn_exec_cb(
+                        insn,
+                        trampoline_insn_exec,
+                        ffi::QEMU_PLUGIN_CB_NO_REGS,
+                        userdata,
+                    );
+                }
+            }
+
+            if state.on_memory_access.is_some() {
+                unsafe {
+                    ffi::qemu_plugin_register_vcpu_mem_cb(
+                        insn,
+                        trampoline_mem_access,
+                        ffi::QEMU_PLUGIN_CB_NO_REGS,
+                        ffi::QEMU_PLUGIN_MEM_RW,
+                        userdata,
+                    );
+                }
+            }
+        }
+    }
+}
+
+extern "C" fn trampoline_insn_exec(_vcpu_index: c_uint, userdata: *mut c_void) {
+    let userdata = unsafe { &*userdata.cast::<InsnUserdata>() };
+    let state = unsafe { &mut *userdata.state };
+    state.last_pc = userdata.pc;
+    if let Some(callback) = state.on_instruction_exec.as_mut() {
+        callback(userdata.pc);
+    }
+}
+
+extern "C" fn trampoline_mem_access(
+    _vcpu_index: c_uint,
+    info: u64,
+    vaddr: u64,
+    userdata: *mut c_void,
+) {
+    let userdata = unsafe { &*userdata.cast::<InsnUserdata>() };
+    let state = unsafe { &mut *userdata.state };
+    if let Some(callback) = state.on_memory_access.as_mut() {
+        let is_write = info & 1 != 0;
+        let size = 1_u8 << ((info >> 4) & 0xF);
+        callback(userdata.pc, vaddr as GuestAddr, size, is_write);
+    }
+}
+
+extern "C" fn trampoline_atexit(_id: ffi::QemuPluginId, userdata: *mut c_void) {
+    let state = unsafe { &mut *userdata.cast::<PluginState>() };
+    if let Some(mut callback) = state.on_atexit.take() {
+        callback();
+    }
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+extern "C" fn trampoline_syscall(
+    id: ffi::QemuPluginId,
+    _vcpu_index: c_uint,
+    num: i64,
+    a1: u64,
+    a2: u64,
+    a3: u64,
+    a4: u64,
+    a5: u64,
+    a6: u64,
+) {
+    let Some(state_ptr) = find_state(id) else {
+        return;
+    };
+    let state = unsafe { &mut *state_ptr };
+    if let Some(callback) = state.on_syscall_pre.as_mut() {
+        let mut args = [
+            a1 as GuestAddr,
+            a2 as GuestAddr,
+            a3 as GuestAddr,
+            a4 as GuestAddr,
+            a5 as GuestAddr,
+            a6 as GuestAddr,
+        ];
+        // `qemu-plugin.h`'s syscall callback is observe-only: there is no
+        // way to rewrite `args` or short-circuit the syscall through this
+        // API, so the returned decision is only meaningful on the patched
+        // backend.
+        callback(state.last_pc, num as i32, &mut args);
+    }
+}
+
+#[allow(clippy::cast_possible_truncation)]
+extern "C" fn trampoline_syscall_ret(id: ffi::QemuPluginId, _vcpu_index: c_uint, num: i64, ret: i64) {
+    let Some(state_ptr) = find_state(id) else {
+        return;
+    };
+    let state = unsafe { &mut *state_ptr };
+    if let Some(callback) = state.on_syscall_post.as_mut() {
+        // Same limitation as `trampoline_syscall`: the rewritten value has
+        // nowhere to go on this backend.
+        callback(num as i32, ret as GuestAddr);
+    }
+}
+
+/// Plugin states, looked up by `id` from trampolines that `qemu-plugin.h`
+/// does not pass a `userdata` pointer to (translate and syscall callbacks).
+/// Every vCPU thread can call into these trampolines, not just the one that
+/// ran [`TcgPluginBackend::install`], so this must be a process-wide store
+/// rather than `thread_local!`. Stored as `usize` rather than `*mut
+/// PluginState` purely so the `Mutex` can be `Send`/`Sync` as a `static`;
+/// the pointers are only ever cast back and dereferenced on the thread
+/// handling a given trampoline call, each of which QEMU serializes per-vCPU.
+static REGISTERED_STATES: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+fn register_state(state: *mut PluginState) {
+    REGISTERED_STATES
+        .lock()
+        .expect("REGISTERED_STATES mutex poisoned")
+        .push(state as usize);
+}
+
+fn unregister_state(state: *mut PluginState) {
+    REGISTERED_STATES
+        .lock()
+        .expect("REGISTERED_STATES mutex poisoned")
+        .retain(|&candidate| candidate != state as usize);
+}
+
+impl QemuBackend for TcgPluginBackend {
+    fn on_translate_block(&mut self, callback: Box<dyn FnMut(GuestAddr) -> bool>) {
+        let state = self.state();
+        state.on_translate_block = Some(callback);
+
+        unsafe {
+            ffi::qemu_plugin_register_vcpu_tb_trans_cb(state.id, trampoline_tb_trans);
+        }
+    }
+
+    fn on_instruction_exec(&mut self, callback: Box<dyn FnMut(GuestAddr)>) {
+        self.state().on_instruction_exec = Some(callback);
+    }
+
+    fn on_memory_access(&mut self, callback: Box<dyn FnMut(GuestAddr, GuestAddr, u8, bool)>) {
+        self.state().on_memory_access = Some(callback);
+    }
+
+    fn on_syscall(
+        &mut self,
+        pre: Box<dyn FnMut(GuestAddr, i32, &mut [GuestAddr; 6]) -> bool>,
+        post: Box<dyn FnMut(i32, GuestAddr) -> GuestAddr>,
+    ) {
+        let state = self.state();
+        state.on_syscall_pre = Some(pre);
+        state.on_syscall_post = Some(post);
+
+        unsafe {
+            ffi::qemu_plugin_register_vcpu_syscall_cb(state.id, trampoline_syscall);
+            ffi::qemu_plugin_register_vcpu_syscall_ret_cb(state.id, trampoline_syscall_ret);
+        }
+    }
+
+    fn flush_jit(&mut self) {
+        let id = self.state().id;
+        unsafe {
+            ffi::qemu_plugin_reset(id, None);
+        }
+    }
+
+    fn atexit(&mut self) {
+        // Nothing to do eagerly: `install` already registered
+        // `trampoline_atexit`, which drains `on_atexit` once QEMU calls it.
+    }
+}
+
+/// Installs a [`TcgPluginBackend`] into a helper tuple's hooks, so every
+/// already-written [`QemuHelper`](crate::helper::QemuHelper) (edge
+/// coverage, memory access, syscalls, ...) drives the same callbacks it
+/// would on patched QEMU, just sourced from the TCG plugin API instead.
+pub fn install_plugin_backend<QT, S>(hooks: &mut QemuHooks<QT, S>, backend: TcgPluginBackend)
+where
+    S: libafl::inputs::UsesInput,
+    QT: crate::helper::QemuHelperTuple<S>,
+{
+    hooks.set_backend(Box::new(backend));
+}
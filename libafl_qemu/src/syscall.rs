@@ -0,0 +1,254 @@
+//! Syscall hooking, for harnessing at the syscall boundary and for feeding a
+//! syscall-site coverage map to the fuzzer.
+use core::fmt::Debug;
+
+use libafl::{
+    executors::ExitKind,
+    inputs::UsesInput,
+    observers::{Observer, ObserversTuple},
+    Error,
+};
+use libafl_bolts::Named;
+use libafl_qemu_sys::GuestAddr;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    helper::{hash_me, HasInstrumentationFilter, QemuFilterList, QemuHelper, QemuHelperTuple},
+    hooks::QemuHooks,
+    Qemu,
+};
+
+/// Number of slots in the syscall-site hit map.
+pub const SYSCALL_MAP_SIZE: usize = 1 << 16;
+
+/// Hit counts for each `hash_me(syscall_nr ^ caller_pc)` slot, read by
+/// [`QemuSyscallObserver`] in `post_exec`.
+pub static mut SYSCALL_HIT_MAP: [u8; SYSCALL_MAP_SIZE] = [0; SYSCALL_MAP_SIZE];
+
+/// Allowlists or denylists syscalls by number.
+pub type QemuSyscallFilter = QemuFilterList<Vec<i32>>;
+
+impl crate::helper::IsFilter for Vec<i32> {
+    type FilterParameter = i32;
+
+    fn allowed(&self, syscall_nr: Self::FilterParameter) -> bool {
+        self.contains(&syscall_nr)
+    }
+}
+
+/// The syscall number, arguments and caller `pc` observed on the last
+/// in-scope syscall, for a harness to read from `pre_exec`/`post_exec`.
+#[derive(Debug, Clone, Copy)]
+pub struct ObservedSyscall {
+    pub syscall_nr: i32,
+    pub args: [GuestAddr; 6],
+    pub caller_pc: GuestAddr,
+}
+
+/// Observes and/or rewrites syscalls, following QEMU's plugin pre/post vCPU
+/// syscall callbacks (number + args, and the return value), filtered through
+/// a [`QemuSyscallFilter`].
+///
+/// A harness can read the last observed syscall via [`Self::last_syscall`],
+/// rewrite arguments or short-circuit the return value by installing
+/// [`Self::with_pre_hook`]/[`Self::with_post_hook`].
+#[derive(Debug)]
+pub struct QemuSyscallHelper {
+    filter: QemuSyscallFilter,
+    last_syscall: Option<ObservedSyscall>,
+    pre_hook: Option<fn(i32, &mut [GuestAddr; 6]) -> bool>,
+    post_hook: Option<fn(i32, GuestAddr) -> GuestAddr>,
+}
+
+impl QemuSyscallHelper {
+    #[must_use]
+    pub fn new(filter: QemuSyscallFilter) -> Self {
+        Self {
+            filter,
+            last_syscall: None,
+            pre_hook: None,
+            post_hook: None,
+        }
+    }
+
+    /// Installs a callback run before an in-scope syscall executes. It may
+    /// rewrite `args` in place; returning `false` short-circuits the syscall
+    /// entirely (it is not issued to the kernel). On
+    /// [`TcgPluginBackend`](crate::plugin::TcgPluginBackend) the hook still
+    /// runs, but rewritten `args` and the returned value have no effect.
+    #[must_use]
+    pub fn with_pre_hook(mut self, hook: fn(i32, &mut [GuestAddr; 6]) -> bool) -> Self {
+        self.pre_hook = Some(hook);
+        self
+    }
+
+    /// Installs a callback run after an in-scope syscall returns, letting a
+    /// harness rewrite the return value before the guest observes it. On
+    /// [`TcgPluginBackend`](crate::plugin::TcgPluginBackend) the hook still
+    /// runs, but the value it returns is not passed back to the guest.
+    #[must_use]
+    pub fn with_post_hook(mut self, hook: fn(i32, GuestAddr) -> GuestAddr) -> Self {
+        self.post_hook = Some(hook);
+        self
+    }
+
+    /// The syscall number, arguments and caller `pc` observed on the last
+    /// in-scope syscall.
+    #[must_use]
+    pub fn last_syscall(&self) -> Option<ObservedSyscall> {
+        self.last_syscall
+    }
+
+    fn record(&self, syscall_nr: i32, caller_pc: GuestAddr) {
+        let slot = (hash_me(syscall_nr as u64 ^ caller_pc as u64) as usize) % SYSCALL_MAP_SIZE;
+        unsafe {
+            SYSCALL_HIT_MAP[slot] = SYSCALL_HIT_MAP[slot].saturating_add(1);
+        }
+    }
+}
+
+impl HasInstrumentationFilter<QemuSyscallFilter> for QemuSyscallHelper {
+    fn filter(&self) -> &QemuSyscallFilter {
+        &self.filter
+    }
+
+    fn filter_mut(&mut self) -> &mut QemuSyscallFilter {
+        &mut self.filter
+    }
+}
+
+fn on_pre_syscall<QT, S>(
+    hooks: &mut QemuHooks<QT, S>,
+    caller_pc: GuestAddr,
+    syscall_nr: i32,
+    args: &mut [GuestAddr; 6],
+) -> bool
+where
+    S: UsesInput,
+    QT: QemuHelperTuple<S>,
+{
+    let helper = hooks
+        .helpers_mut()
+        .match_first_type_mut::<QemuSyscallHelper>()
+        .expect("QemuSyscallHelper not found in the helper tuple");
+
+    if !helper.filter.allowed(syscall_nr) {
+        return true;
+    }
+
+    helper.record(syscall_nr, caller_pc);
+    helper.last_syscall = Some(ObservedSyscall {
+        syscall_nr,
+        args: *args,
+        caller_pc,
+    });
+
+    match helper.pre_hook {
+        Some(hook) => hook(syscall_nr, args),
+        None => true,
+    }
+}
+
+fn on_post_syscall<QT, S>(
+    hooks: &mut QemuHooks<QT, S>,
+    syscall_nr: i32,
+    result: GuestAddr,
+) -> GuestAddr
+where
+    S: UsesInput,
+    QT: QemuHelperTuple<S>,
+{
+    let helper = hooks
+        .helpers_mut()
+        .match_first_type_mut::<QemuSyscallHelper>()
+        .expect("QemuSyscallHelper not found in the helper tuple");
+
+    if !helper.filter.allowed(syscall_nr) {
+        return result;
+    }
+
+    match helper.post_hook {
+        Some(hook) => hook(syscall_nr, result),
+        None => result,
+    }
+}
+
+impl<S> QemuHelper<S> for QemuSyscallHelper
+where
+    S: UsesInput,
+{
+    fn init_hooks<QT>(&self, hooks: &QemuHooks<QT, S>)
+    where
+        QT: QemuHelperTuple<S>,
+    {
+        hooks.syscalls(on_pre_syscall::<QT, S>);
+        hooks.syscalls_after(on_post_syscall::<QT, S>);
+    }
+
+    fn pre_exec(&mut self, _emulator: &Qemu, _input: &S::Input) {
+        self.last_syscall = None;
+    }
+
+    fn post_exec<OT>(
+        &mut self,
+        _emulator: &Qemu,
+        _input: &S::Input,
+        _observers: &mut OT,
+        _exit_kind: &mut ExitKind,
+    ) where
+        OT: ObserversTuple<S>,
+    {
+    }
+}
+
+/// Snapshots [`SYSCALL_HIT_MAP`] as an additional coverage observer.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QemuSyscallObserver {
+    name: String,
+    map: Vec<u8>,
+}
+
+impl QemuSyscallObserver {
+    #[must_use]
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            map: vec![0; SYSCALL_MAP_SIZE],
+        }
+    }
+
+    #[must_use]
+    pub fn map(&self) -> &[u8] {
+        &self.map
+    }
+}
+
+impl Named for QemuSyscallObserver {
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl<S> Observer<S> for QemuSyscallObserver
+where
+    S: UsesInput,
+{
+    fn pre_exec(&mut self, _state: &mut S, _input: &S::Input) -> Result<(), Error> {
+        unsafe {
+            SYSCALL_HIT_MAP.fill(0);
+        }
+        Ok(())
+    }
+
+    fn post_exec(
+        &mut self,
+        _state: &mut S,
+        _input: &S::Input,
+        _exit_kind: &ExitKind,
+    ) -> Result<(), Error> {
+        unsafe {
+            self.map.copy_from_slice(&SYSCALL_HIT_MAP);
+        }
+        Ok(())
+    }
+}